@@ -0,0 +1,251 @@
+//! Export model output to mapping-friendly formats (GeoJSON or KML), so results can be dropped
+//! straight into QGIS or a web map.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use fs_err::File;
+use geojson::{Feature, FeatureCollection, Geometry, Value};
+use kml::{
+    types::{Element, Placemark},
+    Kml, KmlDocument, KmlWriter,
+};
+use serde_json::{to_value, Map};
+use shapefile::dbase::FieldValue;
+use shapefile::{Point, Polygon, PolygonRing, Shape};
+
+use ramp::{Population, MSOA};
+
+#[derive(clap::ArgEnum, Clone, Copy, Debug)]
+pub enum ExportFormat {
+    GeoJson,
+    Kml,
+}
+
+/// One MSOA's worth of output, ready to become a GeoJSON/KML feature
+struct MsoaFeature {
+    msoa: MSOA,
+    shape: Shape,
+    initial_cases: usize,
+    population_size: usize,
+    /// Everyone who has ever left the susceptible state, read back from a completed `RunModel`'s
+    /// time series. `None` if no `metrics_dir` was given, or the MSOA has no recorded days.
+    cumulative_infections: Option<usize>,
+}
+
+/// Load boundary geometry and pair it up with per-MSOA results from `population`, then write the
+/// result to `output` in the requested `format`. If `metrics_dir` points at a completed
+/// `RunModel`'s CSV time series, each feature also gets a `cumulative_infections` count.
+pub fn write(
+    population: &Population,
+    boundaries: &Path,
+    format: ExportFormat,
+    metrics_dir: Option<&Path>,
+    output: &Path,
+) -> Result<()> {
+    let shapes = read_boundaries(boundaries)?;
+    let cumulative_infections = metrics_dir.map(read_cumulative_infections).transpose()?;
+
+    let mut features = Vec::new();
+    for (msoa, initial_cases) in population.initial_cases_per_msoa() {
+        let shape = shapes
+            .get(msoa)
+            .with_context(|| format!("no boundary found for {} in {}", msoa, boundaries.display()))?
+            .clone();
+        let population_size = population.population_size(msoa);
+        features.push(MsoaFeature {
+            msoa: msoa.clone(),
+            shape,
+            initial_cases: *initial_cases,
+            population_size,
+            cumulative_infections: cumulative_infections
+                .as_ref()
+                .and_then(|final_susceptible| final_susceptible.get(msoa))
+                .map(|susceptible| population_size.saturating_sub(*susceptible)),
+        });
+    }
+
+    match format {
+        ExportFormat::GeoJson => write_geojson(&features, output),
+        ExportFormat::Kml => write_kml(&features, output),
+    }
+}
+
+/// Read a `timeseries.csv` written by `timeseries::Recorder` and return the last recorded
+/// susceptible count for each MSOA, the basis for `cumulative_infections`
+fn read_cumulative_infections(metrics_dir: &Path) -> Result<BTreeMap<MSOA, usize>> {
+    let path = metrics_dir.join("timeseries.csv");
+    let mut final_susceptible = BTreeMap::new();
+    let mut reader = csv::Reader::from_reader(
+        File::open(&path).with_context(|| format!("couldn't open {}", path.display()))?,
+    );
+    for result in reader.records() {
+        let record = result?;
+        let msoa = MSOA(record.get(1).context("missing msoa column")?.to_string());
+        let susceptible: usize = record.get(2).context("missing susceptible column")?.parse()?;
+        // Rows are written in day order, so the last one seen per MSOA is the final count
+        final_susceptible.insert(msoa, susceptible);
+    }
+    Ok(final_susceptible)
+}
+
+/// Read a shapefile of MSOA boundaries, keyed by the `MSOA11CD` field in its companion .dbf
+fn read_boundaries(path: &Path) -> Result<BTreeMap<MSOA, Shape>> {
+    let mut reader = shapefile::Reader::from_path(path)
+        .with_context(|| format!("couldn't open shapefile {}", path.display()))?;
+    let mut shapes = BTreeMap::new();
+    for result in reader.iter_shapes_and_records() {
+        let (shape, record) = result?;
+        let code = match record.get("MSOA11CD") {
+            Some(FieldValue::Character(Some(code))) => code.trim().to_string(),
+            _ => bail_missing_field(path)?,
+        };
+        shapes.insert(MSOA(code), shape);
+    }
+    Ok(shapes)
+}
+
+fn bail_missing_field<T>(path: &Path) -> Result<T> {
+    anyhow::bail!("{} has no MSOA11CD field in its attribute table", path.display())
+}
+
+fn write_geojson(features: &[MsoaFeature], output: &Path) -> Result<()> {
+    let collection = FeatureCollection {
+        bbox: None,
+        features: features.iter().map(to_geojson_feature).collect::<Result<_>>()?,
+        foreign_members: None,
+    };
+    fs_err::write(output, collection.to_string())?;
+    Ok(())
+}
+
+fn to_geojson_feature(feature: &MsoaFeature) -> Result<Feature> {
+    let mut properties = Map::new();
+    properties.insert("MSOA11CD".to_string(), to_value(&feature.msoa.0)?);
+    properties.insert("initial_cases".to_string(), to_value(feature.initial_cases)?);
+    properties.insert("population_size".to_string(), to_value(feature.population_size)?);
+    if let Some(cumulative_infections) = feature.cumulative_infections {
+        properties.insert("cumulative_infections".to_string(), to_value(cumulative_infections)?);
+    }
+
+    Ok(Feature {
+        bbox: None,
+        geometry: Some(Geometry::new(shape_to_geojson_value(&feature.shape)?)),
+        id: None,
+        properties: Some(properties),
+        foreign_members: None,
+    })
+}
+
+fn shape_to_geojson_value(shape: &Shape) -> Result<Value> {
+    match shape {
+        Shape::Polygon(polygon) => {
+            let to_ring = |ring: &PolygonRing<Point>| -> Vec<Vec<f64>> {
+                ring.points().iter().map(|p| vec![p.x, p.y]).collect()
+            };
+            let mut polygons: Vec<Vec<Vec<f64>>> = polygon_parts(polygon)?
+                .into_iter()
+                .map(|(outer, inner)| {
+                    std::iter::once(to_ring(outer)).chain(inner.iter().map(|r| to_ring(r))).collect()
+                })
+                .collect();
+            Ok(if polygons.len() == 1 {
+                Value::Polygon(polygons.remove(0))
+            } else {
+                Value::MultiPolygon(polygons)
+            })
+        }
+        _ => anyhow::bail!("only polygon boundaries are supported"),
+    }
+}
+
+/// Group a shapefile polygon's rings into parts by their winding-direction classification: each
+/// `Outer` ring starts a new part, and every `Inner` ring that follows belongs to it as a hole.
+/// Real multi-part MSOAs (islands, disjoint coastal sections) come through as more than one part,
+/// instead of guessing "first ring is outer, everything else is a hole" from ring position.
+fn polygon_parts(polygon: &Polygon) -> Result<Vec<(&PolygonRing<Point>, Vec<&PolygonRing<Point>>)>> {
+    let mut parts: Vec<(&PolygonRing<Point>, Vec<&PolygonRing<Point>>)> = Vec::new();
+    for ring in polygon.rings() {
+        match ring {
+            PolygonRing::Outer(_) => parts.push((ring, Vec::new())),
+            PolygonRing::Inner(_) => parts
+                .last_mut()
+                .context("polygon has an inner ring with no preceding outer ring")?
+                .1
+                .push(ring),
+        }
+    }
+    if parts.is_empty() {
+        anyhow::bail!("polygon has no rings");
+    }
+    Ok(parts)
+}
+
+fn write_kml(features: &[MsoaFeature], output: &Path) -> Result<()> {
+    let placemarks = features
+        .iter()
+        .map(|feature| {
+            let mut description = format!(
+                "initial cases: {}, population size: {}",
+                feature.initial_cases, feature.population_size
+            );
+            if let Some(cumulative_infections) = feature.cumulative_infections {
+                description.push_str(&format!(", cumulative infections: {}", cumulative_infections));
+            }
+            Ok(Kml::Placemark(Placemark {
+                name: Some(feature.msoa.0.clone()),
+                description: Some(description),
+                geometry: Some(shape_to_kml_geometry(&feature.shape)?),
+                ..Default::default()
+            }))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let document = KmlDocument {
+        elements: placemarks,
+        ..Default::default()
+    };
+    let mut writer = KmlWriter::from_writer(File::create(output)?);
+    writer.write(&Kml::KmlDocument(document))?;
+    Ok(())
+}
+
+fn shape_to_kml_geometry(shape: &Shape) -> Result<Element> {
+    match shape {
+        Shape::Polygon(polygon) => {
+            let mut polygons: Vec<kml::types::Polygon> = polygon_parts(polygon)?
+                .into_iter()
+                .map(|(outer, inner)| kml::types::Polygon {
+                    outer: ring_to_kml_linear_ring(outer),
+                    inner: inner.into_iter().map(ring_to_kml_linear_ring).collect(),
+                    ..Default::default()
+                })
+                .collect();
+            Ok(if polygons.len() == 1 {
+                Element::Polygon(polygons.remove(0))
+            } else {
+                Element::MultiGeometry(kml::types::MultiGeometry {
+                    geometries: polygons.into_iter().map(Element::Polygon).collect(),
+                    ..Default::default()
+                })
+            })
+        }
+        _ => anyhow::bail!("only polygon boundaries are supported"),
+    }
+}
+
+fn ring_to_kml_linear_ring(ring: &PolygonRing<Point>) -> kml::types::LinearRing {
+    kml::types::LinearRing {
+        coords: ring
+            .points()
+            .iter()
+            .map(|p| kml::types::Coord {
+                x: p.x,
+                y: p.y,
+                z: None,
+            })
+            .collect(),
+        ..Default::default()
+    }
+}
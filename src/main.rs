@@ -3,9 +3,17 @@
 #[macro_use]
 extern crate log;
 
+mod export;
+mod rrd;
+mod timeseries;
+mod validate;
+
 use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::Duration;
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
+use chrono::NaiveDate;
 use clap::Parser;
 use fs_err::File;
 use rand::rngs::StdRng;
@@ -38,44 +46,175 @@ async fn main() -> Result<()> {
     };
 
     match args.action {
-        Action::Init { region } => {
-            let input = region.to_input().await?;
+        Action::Init { region_args } => {
+            let (input, name) = region_args.build_input().await?;
             let population = Population::create(input, &mut rng).await?;
 
             info!("By the end, {}", utilities::memory_usage());
-            let output = format!("processed_data/{:?}.bin", region);
+            let output = format!("processed_data/{}.bin", name);
             info!("Writing population to {}", output);
             utilities::write_binary(&population, output)?;
         }
-        Action::PythonCache { region } => {
+        Action::PythonCache { region_args } => {
             info!("Loading population");
-            let population =
-                utilities::read_binary::<Population>(format!("processed_data/{:?}.bin", region))?;
-            let output = format!("processed_data/python_cache_{:?}", region);
+            let name = region_args.name()?;
+            let population = utilities::read_binary::<Population>(format!("processed_data/{}.bin", name))?;
+            let output = format!("processed_data/python_cache_{}", name);
             info!("Writing Python cache files to {}", output);
             population.write_python_cache(output)?;
         }
-        Action::Snapshot { region } => {
+        Action::Snapshot {
+            region_args,
+            start_date,
+        } => {
             info!("Loading population");
-            let population =
-                utilities::read_binary::<Population>(format!("processed_data/{:?}.bin", region))?;
-            // TODO Based on input parameters like start-date, maybe trim the lockdown list
-            let output = format!("processed_data/snapshot_{:?}.npz", region);
+            let name = region_args.name()?;
+            let mut population =
+                utilities::read_binary::<Population>(format!("processed_data/{}.bin", name))?;
+            if let Some(start_date) = start_date {
+                population.trim_lockdown(start_date);
+            }
+            let output = format!("processed_data/snapshot_{}.npz", name);
             info!("Writing snapshot to {}", output);
             Snapshot::convert_to_npz(population, output, &mut rng)?;
         }
-        Action::RunModel { region } => {
+        Action::RunModel {
+            region_args,
+            output_format,
+            metrics_dir,
+            run_options,
+        } => {
+            info!("Loading population");
+            let name = region_args.name()?;
+            let population = utilities::read_binary::<Population>(format!("processed_data/{}.bin", name))?;
+            let mut model = run_options.build_model(population, rng)?;
+            let mut recorder = timeseries::Recorder::new(output_format, &metrics_dir, model.num_days())?;
+            model.run(|day, counts_per_msoa| recorder.record_day(day, counts_per_msoa))?;
+            recorder.finish()?;
+        }
+        Action::Export {
+            region_args,
+            format,
+            boundaries,
+            metrics_dir,
+            output,
+        } => {
             info!("Loading population");
-            let population =
-                utilities::read_binary::<Population>(format!("processed_data/{:?}.bin", region))?;
-            let mut model = Model::new(population, rng)?;
-            model.run()?;
+            let name = region_args.name()?;
+            let population = utilities::read_binary::<Population>(format!("processed_data/{}.bin", name))?;
+            info!("Writing {:?} export to {}", format, output.display());
+            export::write(&population, &boundaries, format, metrics_dir.as_deref(), &output)?;
+        }
+        Action::Pipeline {
+            region_args,
+            steps,
+            output_format,
+            metrics_dir,
+            run_options,
+        } => {
+            if let Some(pos) = steps.iter().position(|step| *step == PipelineStep::RunModel) {
+                if pos != steps.len() - 1 {
+                    bail!("RunModel consumes the rng, so it can only be the last step in --steps");
+                }
+            }
+
+            let (input, name) = region_args.build_input().await?;
+            let mut population: Option<Population> = None;
+
+            for step in &steps {
+                match step {
+                    PipelineStep::Init => {
+                        info!("Building population for {}", name);
+                        population = Some(Population::create(input.clone(), &mut rng).await?);
+                        info!("By the end, {}", utilities::memory_usage());
+                    }
+                    PipelineStep::PythonCache => {
+                        let population = population
+                            .as_ref()
+                            .context("PythonCache must come after Init in --steps")?;
+                        let output = format!("processed_data/python_cache_{}", name);
+                        info!("Writing Python cache files to {}", output);
+                        population.write_python_cache(output)?;
+                    }
+                    PipelineStep::Snapshot => {
+                        let mut population = population
+                            .as_ref()
+                            .context("Snapshot must come after Init in --steps")?
+                            .clone();
+                        if let Some(start_date) = run_options.start_date {
+                            population.trim_lockdown(start_date);
+                        }
+                        let output = format!("processed_data/snapshot_{}.npz", name);
+                        info!("Writing snapshot to {}", output);
+                        Snapshot::convert_to_npz(population, output, &mut rng)?;
+                    }
+                    PipelineStep::RunModel => {
+                        let population = population
+                            .take()
+                            .context("RunModel must come after Init in --steps")?;
+                        let mut model = run_options.build_model(population, rng)?;
+                        let mut recorder =
+                            timeseries::Recorder::new(output_format, &metrics_dir, model.num_days())?;
+                        model.run(|day, counts_per_msoa| recorder.record_day(day, counts_per_msoa))?;
+                        recorder.finish()?;
+                        // Checked above: RunModel is always the last step
+                        break;
+                    }
+                }
+            }
+        }
+        Action::Validate { region_args } => {
+            validate::validate_region(region_args).await?;
+        }
+        Action::DumpTimeseries { metrics_dir, output } => {
+            timeseries::dump_rrd(&metrics_dir, &output)?;
         }
     }
 
     Ok(())
 }
 
+/// A built-in `Region` or a custom `--config` file, shared by every action that needs to identify
+/// which run's `processed_data/*` files to read or write
+#[derive(clap::Args, Clone)]
+pub struct RegionArgs {
+    #[clap(arg_enum, required_unless_present = "config")]
+    region: Option<Region>,
+    /// Instead of one of the built-in regions, read an arbitrary set of MSOAs and run parameters
+    /// from a run configuration file
+    #[clap(long, conflicts_with = "region")]
+    config: Option<PathBuf>,
+}
+
+impl RegionArgs {
+    /// The name used for this run's `processed_data/*` files. Unlike `build_input`, this doesn't
+    /// validate MSOAs against the national list, so it's cheap enough to call before every action,
+    /// not just `Init`/`Pipeline`.
+    fn name(&self) -> Result<String> {
+        match (&self.region, &self.config) {
+            (Some(region), None) => Ok(format!("{:?}", region)),
+            (None, Some(config)) => Ok(RunConfig::load(config)?.name),
+            (Some(_), Some(_)) => unreachable!("clap enforces region and config are exclusive"),
+            (None, None) => unreachable!("clap enforces region or config is set"),
+        }
+    }
+
+    /// Resolve an `Init`/`Pipeline` invocation's region-or-config choice into an `Input` and a
+    /// name used for output files
+    async fn build_input(&self) -> Result<(Input, String)> {
+        match (&self.region, &self.config) {
+            (Some(region), None) => Ok((region.to_input().await?, format!("{:?}", region))),
+            (None, Some(config)) => {
+                let run_config = RunConfig::load(config)?;
+                let name = run_config.name.clone();
+                Ok((run_config.to_input().await?, name))
+            }
+            (Some(_), Some(_)) => unreachable!("clap enforces region and config are exclusive"),
+            (None, None) => unreachable!("clap enforces region or config is set"),
+        }
+    }
+}
+
 #[derive(Parser)]
 #[clap(about, version, author)]
 struct Args {
@@ -103,24 +242,123 @@ enum Region {
 enum Action {
     /// Import raw data and build an activity model for a region
     Init {
-        #[clap(arg_enum)]
-        region: Region,
+        #[clap(flatten)]
+        region_args: RegionArgs,
     },
     /// Transform a Population to the Python InitialisationCache format
     PythonCache {
-        #[clap(arg_enum)]
-        region: Region,
+        #[clap(flatten)]
+        region_args: RegionArgs,
     },
     /// Transform a Population into a Snapshot
     Snapshot {
-        #[clap(arg_enum)]
-        region: Region,
+        #[clap(flatten)]
+        region_args: RegionArgs,
+        /// Treat this date as day 0, trimming the lockdown timeline to start from here
+        #[clap(long)]
+        start_date: Option<NaiveDate>,
     },
     /// Run the model, for a fixed number of days
     RunModel {
-        #[clap(arg_enum)]
-        region: Region,
+        #[clap(flatten)]
+        region_args: RegionArgs,
+        /// How to write out the per-day, per-MSOA disease-state time series
+        #[clap(long, arg_enum, default_value = "csv")]
+        output_format: timeseries::OutputFormat,
+        /// Where to write time-series output
+        #[clap(long, default_value = "metrics")]
+        metrics_dir: PathBuf,
+        #[clap(flatten)]
+        run_options: RunOptions,
+    },
+    /// Export a Population's per-MSOA results to a mapping-friendly format
+    Export {
+        #[clap(flatten)]
+        region_args: RegionArgs,
+        #[clap(long, arg_enum, default_value = "geo-json")]
+        format: export::ExportFormat,
+        /// A shapefile of MSOA boundaries, matched to results by the MSOA11CD attribute
+        #[clap(long)]
+        boundaries: PathBuf,
+        /// A completed RunModel's --metrics-dir, used to add a cumulative_infections property
+        #[clap(long)]
+        metrics_dir: Option<PathBuf>,
+        #[clap(long)]
+        output: PathBuf,
+    },
+    /// Run Init, and optionally PythonCache/Snapshot/RunModel, in one process, passing the
+    /// Population between stages directly instead of round-tripping through processed_data/*.bin
+    Pipeline {
+        #[clap(flatten)]
+        region_args: RegionArgs,
+        /// Comma-separated subset of stages to run, in order
+        #[clap(
+            long,
+            arg_enum,
+            use_value_delimiter = true,
+            default_value = "init,run-model"
+        )]
+        steps: Vec<PipelineStep>,
+        /// How to write out the per-day, per-MSOA disease-state time series, if RunModel is one
+        /// of the steps
+        #[clap(long, arg_enum, default_value = "csv")]
+        output_format: timeseries::OutputFormat,
+        /// Where to write time-series output, if RunModel is one of the steps
+        #[clap(long, default_value = "metrics")]
+        metrics_dir: PathBuf,
+        #[clap(flatten)]
+        run_options: RunOptions,
+    },
+    /// Check a region or run-config's input for problems, without building the model
+    Validate {
+        #[clap(flatten)]
+        region_args: RegionArgs,
     },
+    /// Dump a `RunModel --output-format rrd` time series back to a tidy CSV, since the RRD binary
+    /// format itself isn't human-readable
+    DumpTimeseries {
+        /// The --metrics-dir used by a completed `RunModel --output-format rrd`
+        #[clap(long)]
+        metrics_dir: PathBuf,
+        #[clap(long)]
+        output: PathBuf,
+    },
+}
+
+#[derive(clap::ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum PipelineStep {
+    Init,
+    PythonCache,
+    Snapshot,
+    RunModel,
+}
+
+/// Bounds on how long `RunModel` runs for, either in simulated days or wall-clock time, and where
+/// the lockdown timeline is anchored
+#[derive(clap::Args, Clone)]
+struct RunOptions {
+    /// Stop after this many simulated days, instead of running the model's default length
+    #[clap(long)]
+    days: Option<usize>,
+    /// Stop once this long has elapsed in wall-clock time, regardless of how many days have been
+    /// simulated (eg "30m", "2h")
+    #[clap(long)]
+    max_time: Option<humantime::Duration>,
+    /// Treat this date as day 0, trimming the lockdown timeline to start from here
+    #[clap(long)]
+    start_date: Option<NaiveDate>,
+}
+
+impl RunOptions {
+    fn build_model(&self, population: Population, rng: StdRng) -> Result<Model> {
+        Model::new(
+            population,
+            rng,
+            self.days,
+            self.max_time.map(Duration::from),
+            self.start_date,
+        )
+    }
 }
 
 impl Region {
@@ -163,3 +401,71 @@ struct InitialCaseRow {
 fn default_cases() -> usize {
     5
 }
+
+/// A declarative description of a run over an arbitrary set of MSOAs, for users who don't want to
+/// recompile to add a new `Region`. Deserialized from a file passed to `Action::Init`'s `--config`.
+#[derive(Deserialize)]
+struct RunConfig {
+    /// Used to name the output files, eg `processed_data/{name}.bin`
+    name: String,
+    /// MSOA codes to include directly, each seeded with `default_cases` unless `case_csv` already
+    /// gives it a count
+    #[serde(default)]
+    msoas: Vec<MSOA>,
+    /// A CSV of initial cases per MSOA, in the same `MSOA11CD,cases` shape as the built-in region
+    /// files
+    case_csv: Option<PathBuf>,
+}
+
+impl RunConfig {
+    fn load(path: &PathBuf) -> Result<RunConfig> {
+        let run_config: RunConfig = serde_json::from_reader(File::open(path)?)?;
+        if run_config.msoas.is_empty() && run_config.case_csv.is_none() {
+            bail!(
+                "{} doesn't specify any msoas or a case_csv",
+                path.display()
+            );
+        }
+        // `name` becomes a path component (processed_data/{name}.bin, etc), so keep it from
+        // escaping that directory
+        if run_config.name.is_empty()
+            || !run_config
+                .name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            bail!(
+                "{:?} isn't a safe name (only letters, digits, '_' and '-' are allowed)",
+                run_config.name
+            );
+        }
+        Ok(run_config)
+    }
+
+    async fn to_input(&self) -> Result<Input> {
+        let mut input = Input {
+            initial_cases_per_msoa: BTreeMap::new(),
+        };
+        let all_msoas = MSOA::all_msoas_nationally().await?;
+
+        if let Some(csv_path) = &self.case_csv {
+            for rec in csv::Reader::from_reader(File::open(csv_path)?).deserialize() {
+                let rec: InitialCaseRow = rec?;
+                if !all_msoas.contains(&rec.msoa) {
+                    bail!("{} in {} isn't a real MSOA", rec.msoa, csv_path.display());
+                }
+                input.initial_cases_per_msoa.insert(rec.msoa, rec.cases);
+            }
+        }
+        for msoa in &self.msoas {
+            if !all_msoas.contains(msoa) {
+                bail!("{} isn't a real MSOA", msoa);
+            }
+            input
+                .initial_cases_per_msoa
+                .entry(msoa.clone())
+                .or_insert_with(default_cases);
+        }
+        Ok(input)
+    }
+}
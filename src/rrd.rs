@@ -0,0 +1,144 @@
+//! A small fixed-size round-robin database for time-series metrics, so long National runs don't
+//! grow output unboundedly. Loosely modelled on proxmox-rrd's ring buffer design: each metric gets
+//! a fixed number of slots, each holding one consolidated value for a bucket of simulated days.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+/// How to combine multiple readings that land in the same slot. Exposed to `timeseries::Recorder`
+/// so each disease-state metric can pick the summary that makes sense for it, eg the peak
+/// infected count in a slot rather than whichever day happened to land last.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub(crate) enum Consolidation {
+    Last,
+    Average,
+    Max,
+}
+
+/// One metric's fixed-size ring of consolidated slots
+#[derive(Serialize, Deserialize)]
+struct RingBuffer {
+    slots: Vec<f64>,
+    consolidation: Consolidation,
+    steps_per_slot: usize,
+    head: usize,
+    last_update_step: Option<usize>,
+    /// How many distinct buckets have ever been written, capped at `slots.len()`. Once it hits
+    /// that cap the ring has fully wrapped at least once and stays considered wrapped forever
+    /// after, since every slot now holds real (or zero-filled) data rather than its initial `NaN`.
+    buckets_written: usize,
+    /// How many readings have been consolidated into the slot at `head`, for `Average`
+    readings_in_slot: usize,
+}
+
+impl RingBuffer {
+    fn new(num_slots: usize, steps_per_slot: usize, consolidation: Consolidation) -> Self {
+        RingBuffer {
+            slots: vec![f64::NAN; num_slots],
+            consolidation,
+            steps_per_slot,
+            head: 0,
+            last_update_step: None,
+            buckets_written: 0,
+            readings_in_slot: 0,
+        }
+    }
+
+    /// Record a new reading for simulation step `step`, consolidating into the slot it falls in
+    /// and zero-filling any slots skipped since the last update.
+    fn update(&mut self, step: usize, value: f64) {
+        let num_slots = self.slots.len();
+        let slot_index = (step / self.steps_per_slot) % num_slots;
+        let bucket = step / self.steps_per_slot;
+
+        let same_slot_as_last = match self.last_update_step {
+            Some(last_step) => bucket == last_step / self.steps_per_slot,
+            None => false,
+        };
+
+        if !same_slot_as_last {
+            let gap_buckets = match self.last_update_step {
+                Some(last_step) => {
+                    let last_bucket = last_step / self.steps_per_slot;
+                    let gap_buckets = bucket.saturating_sub(last_bucket);
+                    // Past a full lap every slot is stale, including the one at `head`
+                    for i in 1..gap_buckets.min(num_slots + 1) {
+                        let idx = (self.head + i) % num_slots;
+                        self.slots[idx] = 0.0;
+                    }
+                    gap_buckets.min(num_slots)
+                }
+                None => 1,
+            };
+            self.slots[slot_index] = value;
+            self.readings_in_slot = 1;
+            self.buckets_written = (self.buckets_written + gap_buckets).min(num_slots);
+        } else {
+            let existing = self.slots[slot_index];
+            self.readings_in_slot += 1;
+            self.slots[slot_index] = match self.consolidation {
+                Consolidation::Last => value,
+                Consolidation::Average if existing.is_nan() => value,
+                Consolidation::Average => {
+                    existing + (value - existing) / self.readings_in_slot as f64
+                }
+                Consolidation::Max if existing.is_nan() => value,
+                Consolidation::Max => existing.max(value),
+            };
+        }
+        self.head = slot_index;
+        self.last_update_step = Some(step);
+    }
+
+    /// Reconstruct the chronological series, oldest first. Once the ring has wrapped, that means
+    /// rotating the full buffer to start right after the current head; otherwise every slot past
+    /// `head` is still its initial, never-written `NaN`, so just return the written prefix.
+    fn series(&self) -> Vec<f64> {
+        let num_slots = self.slots.len();
+        if self.buckets_written < num_slots {
+            self.slots[..=self.head].to_vec()
+        } else {
+            (0..num_slots)
+                .map(|i| self.slots[(self.head + 1 + i) % num_slots])
+                .collect()
+        }
+    }
+}
+
+/// A fixed-size database of named ring-buffer metrics, eg one per (MSOA, disease state) pair
+#[derive(Serialize, Deserialize)]
+pub struct Rrd {
+    num_slots: usize,
+    steps_per_slot: usize,
+    metrics: BTreeMap<String, RingBuffer>,
+}
+
+impl Rrd {
+    pub fn new(num_slots: usize, steps_per_slot: usize) -> Self {
+        Rrd {
+            num_slots,
+            steps_per_slot,
+            metrics: BTreeMap::new(),
+        }
+    }
+
+    /// Record a reading for `metric`. `consolidation` only takes effect the first time `metric` is
+    /// seen, since that's what decides how later readings in the same slot get combined.
+    pub(crate) fn update(&mut self, metric: &str, step: usize, value: f64, consolidation: Consolidation) {
+        let num_slots = self.num_slots;
+        let steps_per_slot = self.steps_per_slot;
+        self.metrics
+            .entry(metric.to_string())
+            .or_insert_with(|| RingBuffer::new(num_slots, steps_per_slot, consolidation))
+            .update(step, value);
+    }
+
+    /// Every recorded metric, as its chronological (oldest-first) series of consolidated values
+    pub fn series(&self) -> BTreeMap<&str, Vec<f64>> {
+        self.metrics
+            .iter()
+            .map(|(name, buffer)| (name.as_str(), buffer.series()))
+            .collect()
+    }
+}
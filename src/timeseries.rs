@@ -0,0 +1,122 @@
+//! Per-day time-series recording of disease-state counts during `Action::RunModel`.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+
+use ramp::{DayCounts, MSOA};
+
+use crate::rrd::{Consolidation, Rrd};
+
+#[derive(clap::ArgEnum, Clone, Copy, Debug)]
+pub enum OutputFormat {
+    Csv,
+    Rrd,
+}
+
+/// However many days a run covers, consolidate its `--output-format rrd` time series down to this
+/// many slots, so a National run over years doesn't produce an unboundedly large file
+const RRD_NUM_SLOTS: usize = 365;
+
+/// Aggregates per-MSOA disease-state counts, one day at a time, to either a tidy CSV or a
+/// fixed-size round-robin database
+pub enum Recorder {
+    Csv(csv::Writer<fs_err::File>),
+    Rrd { rrd: Rrd, path: PathBuf },
+}
+
+impl Recorder {
+    pub fn new(format: OutputFormat, metrics_dir: &Path, num_days: usize) -> Result<Self> {
+        fs_err::create_dir_all(metrics_dir)?;
+        match format {
+            OutputFormat::Csv => {
+                let mut writer = csv::Writer::from_path(metrics_dir.join("timeseries.csv"))?;
+                writer.write_record(["day", "msoa", "susceptible", "exposed", "infected", "recovered"])?;
+                Ok(Recorder::Csv(writer))
+            }
+            OutputFormat::Rrd => {
+                let steps_per_slot = (num_days.max(1) + RRD_NUM_SLOTS - 1) / RRD_NUM_SLOTS;
+                Ok(Recorder::Rrd {
+                    rrd: Rrd::new(RRD_NUM_SLOTS, steps_per_slot),
+                    path: metrics_dir.join("timeseries_rrd.bin"),
+                })
+            }
+        }
+    }
+
+    pub fn record_day(&mut self, day: usize, counts_per_msoa: &BTreeMap<MSOA, DayCounts>) -> Result<()> {
+        match self {
+            Recorder::Csv(writer) => {
+                for (msoa, counts) in counts_per_msoa {
+                    writer.write_record(&[
+                        day.to_string(),
+                        msoa.0.clone(),
+                        counts.susceptible.to_string(),
+                        counts.exposed.to_string(),
+                        counts.infected.to_string(),
+                        counts.recovered.to_string(),
+                    ])?;
+                }
+            }
+            Recorder::Rrd { rrd, .. } => {
+                for (msoa, counts) in counts_per_msoa {
+                    // susceptible/recovered only ever move in one direction, so the slot's last
+                    // reading represents it; infected is consolidated to its peak, since that's
+                    // what matters for capacity planning, and exposed to its average load
+                    rrd.update(
+                        &format!("{}.susceptible", msoa.0),
+                        day,
+                        counts.susceptible as f64,
+                        Consolidation::Last,
+                    );
+                    rrd.update(
+                        &format!("{}.exposed", msoa.0),
+                        day,
+                        counts.exposed as f64,
+                        Consolidation::Average,
+                    );
+                    rrd.update(
+                        &format!("{}.infected", msoa.0),
+                        day,
+                        counts.infected as f64,
+                        Consolidation::Max,
+                    );
+                    rrd.update(
+                        &format!("{}.recovered", msoa.0),
+                        day,
+                        counts.recovered as f64,
+                        Consolidation::Last,
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<()> {
+        match self {
+            Recorder::Csv(mut writer) => {
+                writer.flush()?;
+                Ok(())
+            }
+            Recorder::Rrd { rrd, path } => ramp::utilities::write_binary(&rrd, path),
+        }
+    }
+}
+
+/// Read back a `timeseries_rrd.bin` written by `Recorder::Rrd` and dump its consolidated series to
+/// a tidy CSV, since the RRD binary format itself isn't something anything else reads
+pub fn dump_rrd(metrics_dir: &Path, output: &Path) -> Result<()> {
+    let rrd: Rrd = ramp::utilities::read_binary(metrics_dir.join("timeseries_rrd.bin"))?;
+
+    let mut writer = csv::Writer::from_path(output)?;
+    writer.write_record(["metric", "slot", "value"])?;
+    for (metric, series) in rrd.series() {
+        for (slot, value) in series.into_iter().enumerate() {
+            writer.write_record(&[metric.to_string(), slot.to_string(), value.to_string()])?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
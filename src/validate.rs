@@ -0,0 +1,120 @@
+//! Cheap pre-flight checks for a region or run-config's input, run ahead of the expensive
+//! `Population::create` call that `Action::Init`/`Action::Pipeline` start with.
+
+use std::collections::HashSet;
+use std::path::Path;
+
+use anyhow::{bail, Result};
+use fs_err::File;
+
+use ramp::MSOA;
+
+use crate::{InitialCaseRow, Region, RegionArgs, RunConfig};
+
+/// One problem found while validating a run's input, ready to print in a report
+struct Problem(String);
+
+pub async fn validate_region(region_args: RegionArgs) -> Result<()> {
+    let problems = match (region_args.region, region_args.config) {
+        (Some(region), None) => check_region(region).await?,
+        (None, Some(config)) => check_config(&config).await?,
+        (Some(_), Some(_)) => unreachable!("clap enforces region and config are exclusive"),
+        (None, None) => unreachable!("clap enforces region or config is set"),
+    };
+
+    if problems.is_empty() {
+        info!("All checks passed");
+        return Ok(());
+    }
+    for problem in &problems {
+        error!("{}", problem.0);
+    }
+    bail!("{} problem(s) found", problems.len());
+}
+
+async fn check_region(region: Region) -> Result<Vec<Problem>> {
+    let mut problems = check_model_parameters_dir();
+    let csv_input = match region {
+        Region::WestYorkshireSmall => "Input_Test_3.csv",
+        Region::WestYorkshireLarge => "Input_WestYorkshire.csv",
+        Region::Devon => "Input_Devon.csv",
+        Region::TwoCounties => "Input_Test_accross.csv",
+        // There's no input CSV for National; every MSOA is used with the default case count
+        Region::National => return Ok(problems),
+    };
+    problems.extend(check_case_csv(&format!("model_parameters/{}", csv_input)).await?);
+    Ok(problems)
+}
+
+async fn check_config(path: &Path) -> Result<Vec<Problem>> {
+    let mut problems = check_model_parameters_dir();
+    let run_config = match RunConfig::load(&path.to_path_buf()) {
+        Ok(run_config) => run_config,
+        Err(err) => {
+            problems.push(Problem(format!("{} doesn't parse: {:#}", path.display(), err)));
+            return Ok(problems);
+        }
+    };
+
+    let all_msoas = MSOA::all_msoas_nationally().await?;
+    let mut seen = HashSet::new();
+    for msoa in &run_config.msoas {
+        if !all_msoas.contains(msoa) {
+            problems.push(Problem(format!("{} isn't a real MSOA", msoa)));
+        }
+        if !seen.insert(msoa.clone()) {
+            problems.push(Problem(format!("{} is listed more than once in msoas", msoa)));
+        }
+    }
+    if let Some(case_csv) = &run_config.case_csv {
+        problems.extend(check_case_csv(&case_csv.to_string_lossy()).await?);
+    }
+    Ok(problems)
+}
+
+/// Check that `model_parameters/` itself is present, since `Population::create` reads fixed
+/// parameter files from it (disease progression tables, lockdown timelines, etc) regardless of
+/// which region or config is chosen.
+///
+/// This snapshot doesn't have visibility into `ramp`'s own list of fixed parameter files, so
+/// unlike `check_case_csv` this can't confirm each one parses too -- only that a completely
+/// missing directory is caught here instead of failing confusingly deep inside `Population::create`.
+fn check_model_parameters_dir() -> Vec<Problem> {
+    if Path::new("model_parameters").is_dir() {
+        Vec::new()
+    } else {
+        vec![Problem("model_parameters/ doesn't exist".to_string())]
+    }
+}
+
+/// Check that a CSV of initial cases parses, every MSOA is real, there are no duplicate rows, and
+/// every case count is non-negative
+async fn check_case_csv(path: &str) -> Result<Vec<Problem>> {
+    let mut problems = Vec::new();
+
+    if !Path::new(path).exists() {
+        problems.push(Problem(format!("{} doesn't exist", path)));
+        return Ok(problems);
+    }
+
+    let all_msoas = MSOA::all_msoas_nationally().await?;
+    let mut seen = HashSet::new();
+    let mut reader = csv::Reader::from_reader(File::open(path)?);
+    for (line, rec) in reader.deserialize::<InitialCaseRow>().enumerate() {
+        let rec = match rec {
+            Ok(rec) => rec,
+            Err(err) => {
+                problems.push(Problem(format!("{} line {}: {}", path, line + 2, err)));
+                continue;
+            }
+        };
+        if !all_msoas.contains(&rec.msoa) {
+            problems.push(Problem(format!("{} in {} isn't a real MSOA", rec.msoa, path)));
+        }
+        if !seen.insert(rec.msoa.clone()) {
+            problems.push(Problem(format!("{} is listed more than once in {}", rec.msoa, path)));
+        }
+        // `cases` deserializes as a usize, so the CSV parse above already rejects negative values
+    }
+    Ok(problems)
+}